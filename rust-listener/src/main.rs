@@ -16,64 +16,548 @@ email: george.swindells@totalperformancedata.com
 
 use std::env;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::net::UdpSocket;
 use std::str::from_utf8;
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, BufWriter};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use redis;
 use redis::{RedisResult, Connection};
 
 static HOST: &'static str = "0.0.0.0";
 static PORT: i32 = 33322;
+static INITIAL_BACKOFF_MS: u64 = 100;
+static MAX_BACKOFF_MS: u64 = 30_000;
+// schemes the redis crate understands: plain TCP, TLS, and unix domain socket
+// (redis+unix carries a query-string db selector, unix is the bare form)
+static VALID_SCHEMES: [&'static str; 4] = ["redis", "rediss", "redis+unix", "unix"];
+static DEFAULT_FS_SINK_DIR: &'static str = "./gmaxfeed_data";
+static DEFAULT_FS_MAX_BYTES: u64 = 64 * 1024 * 1024; // roll at 64 MiB
+static SECONDS_PER_DAY: u64 = 86_400;
+static DEFAULT_CHANNEL_CAPACITY: usize = 10_000;
+static DROP_LOG_INTERVAL: u64 = 100; // log every Nth dropped packet, not every one
+static UNDECODABLE_LOG_INTERVAL: u64 = 100; // log every Nth non-UTF-8 packet, not every one
+static BATCH_SIZE: usize = 200; // max packets flushed to redis in one pipelined round trip
+static BATCH_TIMEOUT_MS: u64 = 50; // flush sooner than BATCH_SIZE if traffic is light
 
 
-fn handle_with_filesystem() {
-    println!("handling with filesystem from within rust");
-    // TODO
+// how to react when the channel between listen() and a sink's consumer
+// thread is full: apply backpressure to the UDP receive loop, or keep
+// receiving and throw away the oldest buffered packet instead. Mirrors the
+// backpressure-yielding strategy flodgatt adopted for its largest instances.
+enum OverflowPolicy {
+    Block,
+    DropOldest,
 }
 
+impl OverflowPolicy {
+    fn from_str(s: &str) -> OverflowPolicy {
+        match s {
+            "block" => OverflowPolicy::Block,
+            "drop_oldest" => OverflowPolicy::DropOldest,
+            other => panic!("Unsupported channel overflow policy '{}', expected block or drop_oldest", other),
+        }
+    }
 
-fn handle_with_redis(rx: &Receiver<String>) -> RedisResult<Connection> {
-    // handle the string packets received by putting into redis Queue as string
-    // can also add a fire and forget PUBSUB
-    let redis_password = match env::var("REDIS_PASSWD") {
-        Ok(val) => val,
-        Err(_e) => String::from("NONE"),
-    };
-    let redis_url = format!("redis://:{}@127.0.0.1:6379/", redis_password);
-    let client = redis::Client::open(redis_url).unwrap();
-    let mut conn = client.get_connection()?; // returns error if not successful
+    fn from_env() -> OverflowPolicy {
+        match env::var("CHANNEL_OVERFLOW_POLICY") {
+            Ok(val) => OverflowPolicy::from_str(&val),
+            Err(_e) => OverflowPolicy::Block,
+        }
+    }
+}
+
+
+// a fixed-capacity mpsc queue. Unlike std::sync::mpsc::sync_channel, a
+// DropOldest policy can evict the head of the queue itself rather than
+// only ever blocking the sender, so a slow sink can't grow the process's
+// memory without bound.
+struct BoundedQueue {
+    capacity: usize,
+    policy: OverflowPolicy,
+    deque: Mutex<VecDeque<Vec<u8>>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    dropped: AtomicU64,
+    senders: AtomicUsize,
+}
+
+struct BoundedSender {
+    inner: Arc<BoundedQueue>,
+}
+
+struct BoundedReceiver {
+    inner: Arc<BoundedQueue>,
+}
+
+impl Clone for BoundedSender {
+    fn clone(&self) -> BoundedSender {
+        self.inner.senders.fetch_add(1, Ordering::SeqCst);
+        BoundedSender { inner: self.inner.clone() }
+    }
+}
+
+impl Drop for BoundedSender {
+    // wake any receiver blocked in recv() once the last sender is gone, so it
+    // can observe the closed queue instead of waiting forever
+    fn drop(&mut self) {
+        if self.inner.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.inner.deque.lock().unwrap();
+            self.inner.not_empty.notify_all();
+        }
+    }
+}
+
+impl BoundedSender {
+    fn send(&self, packet: Vec<u8>) {
+        let mut deque = self.inner.deque.lock().unwrap();
+        match self.inner.policy {
+            OverflowPolicy::Block => {
+                while deque.len() >= self.inner.capacity {
+                    deque = self.inner.not_full.wait(deque).unwrap();
+                }
+                deque.push_back(packet);
+            }
+            OverflowPolicy::DropOldest => {
+                self.evict_if_full(&mut deque);
+                deque.push_back(packet);
+            }
+        }
+        self.inner.not_empty.notify_one();
+    }
+
+    // like send(), but never blocks: under the Block policy a full queue
+    // makes this return false with the packet not enqueued, so a caller on a
+    // hot path can hand the blocking wait off to somewhere else instead of
+    // stalling itself. DropOldest never needs to
+    // block in the first place, so it always succeeds, same as send().
+    fn try_send(&self, packet: Vec<u8>) -> bool {
+        let mut deque = self.inner.deque.lock().unwrap();
+        match self.inner.policy {
+            OverflowPolicy::Block => {
+                if deque.len() >= self.inner.capacity {
+                    return false;
+                }
+                deque.push_back(packet);
+            }
+            OverflowPolicy::DropOldest => {
+                self.evict_if_full(&mut deque);
+                deque.push_back(packet);
+            }
+        }
+        self.inner.not_empty.notify_one();
+        true
+    }
+
+    fn evict_if_full(&self, deque: &mut VecDeque<Vec<u8>>) {
+        if deque.len() >= self.inner.capacity {
+            deque.pop_front();
+            let dropped = self.inner.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_multiple_of(DROP_LOG_INTERVAL) {
+                eprintln!("Channel full, dropped {} packets so far", dropped);
+            }
+        }
+    }
+}
+
+impl BoundedReceiver {
+    // blocks until a packet is available, returns None once every sender has
+    // been dropped and the queue has drained (mirrors mpsc::Receiver::recv)
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut deque = self.inner.deque.lock().unwrap();
+        loop {
+            if let Some(item) = deque.pop_front() {
+                self.inner.not_full.notify_one();
+                return Some(item);
+            }
+            if self.inner.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            deque = self.inner.not_empty.wait(deque).unwrap();
+        }
+    }
+
+    // block for the first packet, then keep draining whatever is already
+    // queued (without waiting long for more) until `max` packets are
+    // collected or `timeout` has elapsed since the first one arrived. Lets
+    // a consumer flush a batch in one round trip instead of one at a time.
+    fn recv_batch(&self, max: usize, timeout: Duration) -> Vec<Vec<u8>> {
+        let mut batch = Vec::with_capacity(max);
+        match self.recv() {
+            Some(packet) => batch.push(packet),
+            None => return batch,
+        }
+
+        let deadline = Instant::now() + timeout;
+        while batch.len() < max {
+            let mut deque = self.inner.deque.lock().unwrap();
+            while deque.is_empty() {
+                let now = Instant::now();
+                if now >= deadline {
+                    return batch;
+                }
+                let (guard, timeout_result) = self.inner.not_empty.wait_timeout(deque, deadline - now).unwrap();
+                deque = guard;
+                if timeout_result.timed_out() && deque.is_empty() {
+                    return batch;
+                }
+            }
+            let item = deque.pop_front().unwrap();
+            self.inner.not_full.notify_one();
+            drop(deque);
+            batch.push(item);
+        }
+        batch
+    }
+}
+
+fn bounded_channel(capacity: usize, policy: OverflowPolicy) -> (BoundedSender, BoundedReceiver) {
+    let inner = Arc::new(BoundedQueue {
+        capacity,
+        policy,
+        deque: Mutex::new(VecDeque::with_capacity(capacity)),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        dropped: AtomicU64::new(0),
+        senders: AtomicUsize::new(1),
+    });
+    (BoundedSender { inner: inner.clone() }, BoundedReceiver { inner })
+}
+
+fn channel_capacity_from_env() -> usize {
+    env::var("CHANNEL_CAPACITY")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_CHANNEL_CAPACITY)
+}
+
+
+// where to find redis and, if set, which logical DB to SELECT once connected.
+// Built from REDIS_URL if set (so operators can point at a rediss:// TLS
+// endpoint or a redis+unix:///unix socket directly), otherwise assembled from
+// the individual REDIS_HOST/REDIS_PORT/REDIS_PASSWD/REDIS_DB vars for
+// backwards compatibility with the old hard-coded 127.0.0.1:6379 setup.
+//
+// `db` is only `Some` when we need an explicit SELECT after connecting. The
+// redis crate already parses a DB out of a REDIS_URL path/query (e.g.
+// `redis://host:6379/3` or `redis+unix:///path?db=3`) and selects it itself
+// during the handshake, so re-issuing SELECT with a default of 0 on every
+// (re)connect would silently bounce such URLs back to db 0. We only add our
+// own SELECT when REDIS_DB was explicitly given, or when there's no URL to
+// carry a DB in the first place.
+struct ConnectionConfig {
+    url: String,
+    db: Option<i64>,
+}
+
+impl ConnectionConfig {
+    fn from_env() -> ConnectionConfig {
+        let explicit_db = env::var("REDIS_DB").ok().and_then(|v| v.parse::<i64>().ok());
+
+        if let Ok(url) = env::var("REDIS_URL") {
+            let scheme = url.split("://").next().unwrap_or("");
+            if !VALID_SCHEMES.contains(&scheme) {
+                panic!("Unsupported REDIS_URL scheme '{}', expected one of {:?}", scheme, VALID_SCHEMES);
+            }
+            return ConnectionConfig { url, db: explicit_db };
+        }
+
+        let host = env::var("REDIS_HOST").unwrap_or_else(|_e| String::from("127.0.0.1"));
+        let port = env::var("REDIS_PORT").unwrap_or_else(|_e| String::from("6379"));
+        let redis_password = env::var("REDIS_PASSWD").unwrap_or_else(|_e| String::from("NONE"));
+        let url = format!("redis://:{}@{}:{}/", redis_password, host, port);
+        ConnectionConfig { url, db: Some(explicit_db.unwrap_or(0)) }
+    }
+}
+
+
+// LPUSH onto a queue, PUBLISH to a channel, or both at once, mirroring
+// flodgatt's REDIS_NAMESPACE idea so several feed listeners can share one
+// redis without clobbering each other's keys/channels.
+enum DeliveryMode {
+    Queue,
+    Pubsub,
+    Both,
+}
+
+impl DeliveryMode {
+    fn from_str(s: &str) -> DeliveryMode {
+        match s {
+            "queue" => DeliveryMode::Queue,
+            "pubsub" => DeliveryMode::Pubsub,
+            "both" => DeliveryMode::Both,
+            other => panic!("Unsupported delivery mode '{}', expected queue, pubsub or both", other),
+        }
+    }
+}
+
+struct RoutingConfig {
+    mode: DeliveryMode,
+    queue_key: String,
+    channel: String,
+}
+
+impl RoutingConfig {
+    fn from_env(mode_override: Option<&str>) -> RoutingConfig {
+        let mode = match mode_override {
+            Some(m) => DeliveryMode::from_str(m),
+            None => match env::var("REDIS_MODE") {
+                Ok(val) => DeliveryMode::from_str(&val),
+                Err(_e) => DeliveryMode::Queue,
+            },
+        };
+        let namespace = env::var("REDIS_NAMESPACE").unwrap_or_else(|_e| String::new());
+        let queue_key = env::var("REDIS_QUEUE_KEY").unwrap_or_else(|_e| String::from("test_queue"));
+        let channel = env::var("REDIS_CHANNEL").unwrap_or_else(|_e| String::from("gmaxfeed"));
+        RoutingConfig {
+            mode,
+            queue_key: format!("{}{}", namespace, queue_key),
+            channel: format!("{}{}", namespace, channel),
+        }
+    }
+
+    // append the commands for one packet onto a pipeline, as binary args so a
+    // packet that isn't valid UTF-8 is stored exactly as received
+    fn push_commands(&self, pipe: &mut redis::Pipeline, packet: &[u8]) {
+        match self.mode {
+            DeliveryMode::Queue => {
+                pipe.cmd("LPUSH").arg(&self.queue_key).arg(packet).ignore();
+            }
+            DeliveryMode::Pubsub => {
+                pipe.cmd("PUBLISH").arg(&self.channel).arg(packet).ignore();
+            }
+            DeliveryMode::Both => {
+                pipe.cmd("LPUSH").arg(&self.queue_key).arg(packet).ignore();
+                pipe.cmd("PUBLISH").arg(&self.channel).arg(packet).ignore();
+            }
+        }
+    }
+
+    // flush a whole batch of packets in one pipelined round trip instead of
+    // one LPUSH/PUBLISH per packet
+    fn dispatch_batch(&self, conn: &mut Connection, batch: &[Vec<u8>]) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for packet in batch {
+            self.push_commands(&mut pipe, packet);
+        }
+        pipe.query(conn)
+    }
+}
+
+
+fn current_day() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / SECONDS_PER_DAY
+}
+
+
+// line-delimited append-only log under base_dir, rolling onto a new file
+// whenever the day changes or the current file passes max_bytes
+struct FilesystemSink {
+    base_dir: PathBuf,
+    max_bytes: u64,
+    day: u64,
+    seq: u64,
+    bytes_written: u64,
+    writer: BufWriter<File>,
+}
+
+impl FilesystemSink {
+    fn new(base_dir: PathBuf, max_bytes: u64) -> FilesystemSink {
+        fs::create_dir_all(&base_dir).expect("Failed to create filesystem sink base dir");
+        let day = current_day();
+        let seq = 0;
+        let (writer, bytes_written) = FilesystemSink::open_file(&base_dir, day, seq);
+        FilesystemSink { base_dir, max_bytes, day, seq, bytes_written, writer }
+    }
+
+    // keep retrying with the same backoff as connect_with_retry rather than
+    // panicking, so a transient I/O error (full disk, fd limit, permissions)
+    // during rotation doesn't take the whole filesystem-sink thread down
+    fn open_file(base_dir: &Path, day: u64, seq: u64) -> (BufWriter<File>, u64) {
+        let path = base_dir.join(format!("packets-{}-{}.log", day, seq));
+        let mut backoff_ms = INITIAL_BACKOFF_MS;
+        loop {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => {
+                    let bytes_written = file.metadata().map(|m| m.len()).unwrap_or(0);
+                    return (BufWriter::new(file), bytes_written);
+                }
+                Err(e) => eprintln!("Failed to open filesystem sink file {:?}: {}, retrying in {}ms", path, e, backoff_ms),
+            }
+            thread::sleep(Duration::from_millis(backoff_ms));
+            backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+        }
+    }
+
+    fn roll(&mut self, new_day: bool) {
+        if new_day {
+            self.day = current_day();
+            self.seq = 0;
+        } else {
+            self.seq += 1;
+        }
+        let (writer, bytes_written) = FilesystemSink::open_file(&self.base_dir, self.day, self.seq);
+        self.writer = writer;
+        self.bytes_written = bytes_written;
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) {
+        if current_day() != self.day {
+            self.roll(true);
+        } else if self.bytes_written >= self.max_bytes {
+            self.roll(false);
+        }
+
+        let write_result = self.writer.write_all(packet).and_then(|_| self.writer.write_all(b"\n"));
+        if let Err(e) = write_result {
+            eprintln!("Failed to write packet to filesystem sink: {}", e);
+            return;
+        }
+        self.bytes_written += packet.len() as u64 + 1;
+        if let Err(e) = self.writer.flush() {
+            eprintln!("Failed to flush filesystem sink: {}", e);
+        }
+    }
+}
+
+
+fn handle_with_filesystem(rx: &BoundedReceiver) {
+    // dependency-free capture mode for debugging/replay when no redis is available
+    let base_dir = PathBuf::from(env::var("FS_SINK_DIR").unwrap_or_else(|_e| String::from(DEFAULT_FS_SINK_DIR)));
+    let max_bytes = env::var("FS_SINK_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_FS_MAX_BYTES);
+    let mut sink = FilesystemSink::new(base_dir, max_bytes);
 
     loop {
-        // element in queue, execute the code to deal with it
-        let res = match rx.recv() {
-               Ok(v) => v,
-               Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+        match rx.recv() {
+            Some(packet) => sink.write_packet(&packet),
+            None => {
+                eprintln!("Channel closed, stopping filesystem handler");
+                return;
+            }
+        }
+    }
+}
+
+
+// keep trying to open a connection, doubling the wait between attempts up to
+// MAX_BACKOFF_MS, confirm the link is actually alive with a PING, and SELECT
+// the configured DB (if any) before handing it back, same as flodgatt does on
+// reconnect.
+fn connect_with_retry(config: &ConnectionConfig) -> Connection {
+    let mut backoff_ms = INITIAL_BACKOFF_MS;
+    loop {
+        let attempt: RedisResult<Connection> = redis::Client::open(config.url.as_str())
+            .and_then(|client| client.get_connection())
+            .and_then(|mut conn| redis::cmd("PING").query::<String>(&mut conn).map(|_| conn))
+            .and_then(|mut conn| match config.db {
+                Some(db) => redis::cmd("SELECT").arg(db).query::<()>(&mut conn).map(|_| conn),
+                None => Ok(conn),
+            });
+
+        match attempt {
+            Ok(conn) => return conn,
+            Err(e) => eprintln!("Redis connect failed: {}, retrying in {}ms", e, backoff_ms),
+        }
+        thread::sleep(Duration::from_millis(backoff_ms));
+        backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+    }
+}
+
+
+fn handle_with_redis(rx: &BoundedReceiver, config: ConnectionConfig, routing: RoutingConfig) {
+    // flush batches of binary packets into the redis queue, a pubsub channel,
+    // or both, depending on the routing config
+    let mut conn = connect_with_retry(&config);
+    // batch that failed to flush on the old connection and still needs sending
+    let mut retry_batch: Option<Vec<Vec<u8>>> = None;
+
+    loop {
+        let batch = match retry_batch.take() {
+            Some(b) => b,
+            None => {
+                let b = rx.recv_batch(BATCH_SIZE, Duration::from_millis(BATCH_TIMEOUT_MS));
+                if b.is_empty() {
+                    eprintln!("Channel closed, stopping redis handler");
+                    return;
+                }
+                b
+            }
         };
-        let _ : () = redis::cmd("LPUSH").arg("test_queue").arg(res).query(&mut conn)?;
+
+        if let Err(e) = routing.dispatch_batch(&mut conn, &batch) {
+            eprintln!("Redis batch dispatch failed: {}, reconnecting...", e);
+            retry_batch = Some(batch);
+            conn = connect_with_retry(&config);
+        }
     }
 }
 
 
-fn listen(tx: & Sender<String>, port_number:i32) {
+fn listen(txs: &[BoundedSender], port_number:i32) {
     let mut buffer: [u8; 2048] = [0; 2048];
     //let addr = SocketAddr::from((HOST, PORT));
     //let addrs = addr.to_socket_addrs().unwrap();
     let socket = UdpSocket::bind(format!("0.0.0.0:{}", port_number)).expect(&format!("Failed to bind to address {0}:{1}", HOST, port_number));
     println!("Bound to socket on {0}:{1}, listening...", HOST, port_number);
+    // packets that aren't valid UTF-8 are still forwarded as-is; this just
+    // tracks how many so operators can tell if something upstream is corrupt
+    let mut undecodable: u64 = 0;
     loop{
         let (amt, _src) = socket.recv_from(&mut buffer).expect("Didn't receive data");
         let _reaction = {
-            let msg = match from_utf8(&buffer[0..amt]) {
-                    Ok(v) => v,
-                    Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                };
-            let s = String::from(msg);
-            tx.send(s).unwrap();
+            if from_utf8(&buffer[0..amt]).is_err() {
+                undecodable += 1;
+                if undecodable.is_multiple_of(UNDECODABLE_LOG_INTERVAL) {
+                    eprintln!("Received {} non-UTF-8 packets so far, still forwarding as binary", undecodable);
+                }
+            }
+            let packet = buffer[0..amt].to_vec();
+            for tx in txs {
+                // under Block a full queue stalls this loop until that sink's
+                // consumer frees a slot, applying backpressure to the socket;
+                // under DropOldest the oldest buffered packet is evicted and
+                // this never waits
+                tx.send(packet.clone());
+            }
         };
     }
 }
 
 
+// which sink(s) to hand received packets off to
+enum Sink {
+    Redis,
+    Filesystem,
+    Both,
+}
+
+impl Sink {
+    fn from_str(s: &str) -> Sink {
+        match s {
+            "redis" => Sink::Redis,
+            "filesystem" => Sink::Filesystem,
+            "both" => Sink::Both,
+            other => panic!("Unsupported sink '{}', expected redis, filesystem or both", other),
+        }
+    }
+
+    fn wants_redis(&self) -> bool {
+        matches!(self, Sink::Redis | Sink::Both)
+    }
+
+    fn wants_filesystem(&self) -> bool {
+        matches!(self, Sink::Filesystem | Sink::Both)
+    }
+}
+
+
 fn main() {
     println!("Starting Rust Gmax feed listner...");
     // get the port number from the command line argument, if none given then use the global hardcoded val. Program name is arg[0]
@@ -85,17 +569,104 @@ fn main() {
     } else {
         println!("Using default port number: {}", port_number);
     }
+    // optional delivery mode override (queue/pubsub/both), falls back to REDIS_MODE
+    let mode_arg = args.get(2).cloned();
+    // optional sink override (redis/filesystem/both), falls back to FEED_SINK
+    let sink = match args.get(3) {
+        Some(s) => Sink::from_str(s),
+        None => match env::var("FEED_SINK") {
+            Ok(val) => Sink::from_str(&val),
+            Err(_e) => Sink::Redis,
+        },
+    };
 
-    // initialise the inter-thread communication
-    let (tx, rx): (Sender<String>, Receiver<String>)  = channel();
-    //let test_msg = String::from("Hello, this is a test message");
-    //tx.send(test_msg).unwrap();
-    // spawn the child thread which performs packet management
-    let _child_thread = thread::spawn(move || {
-        let _thr = match handle_with_redis(&rx){
-            Ok(r) => r,
-            Err(error) => panic!("Problem spawning handler: {:?}", error),
-        };
-    });
-    listen(&tx, port_number);
+    // build and validate the redis config up front, on the main thread,
+    // before any consumer thread is spawned. from_env() panics on a bad
+    // REDIS_URL scheme or REDIS_MODE; doing that here fails the whole
+    // process immediately instead of only killing a background thread while
+    // listen() keeps running with a dead consumer.
+    let redis_setup = if sink.wants_redis() {
+        Some((ConnectionConfig::from_env(), RoutingConfig::from_env(mode_arg.as_deref())))
+    } else {
+        None
+    };
+
+    // one bounded channel per active sink, each with its own consumer thread.
+    // bounding the queue means a stalled sink applies backpressure (or sheds
+    // the oldest packets) instead of growing memory without bound.
+    let capacity = channel_capacity_from_env();
+    let mut senders: Vec<BoundedSender> = Vec::new();
+
+    if let Some((config, routing)) = redis_setup {
+        let (tx, rx) = bounded_channel(capacity, OverflowPolicy::from_env());
+        senders.push(tx);
+        thread::spawn(move || {
+            handle_with_redis(&rx, config, routing);
+        });
+    }
+    if sink.wants_filesystem() {
+        let (tx, rx) = bounded_channel(capacity, OverflowPolicy::from_env());
+        senders.push(tx);
+        thread::spawn(move || {
+            handle_with_filesystem(&rx);
+        });
+    }
+
+    listen(&senders, port_number);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_oldest_evicts_the_oldest_packet() {
+        let (tx, rx) = bounded_channel(2, OverflowPolicy::DropOldest);
+        tx.send(b"a".to_vec());
+        tx.send(b"b".to_vec());
+        tx.send(b"c".to_vec()); // queue is full, "a" should be evicted
+        assert_eq!(rx.recv().unwrap(), b"b".to_vec());
+        assert_eq!(rx.recv().unwrap(), b"c".to_vec());
+    }
+
+    #[test]
+    fn block_policy_applies_backpressure_until_receiver_drains() {
+        let (tx, rx) = bounded_channel(1, OverflowPolicy::Block);
+        tx.send(b"a".to_vec());
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let blocked_tx = tx.clone();
+        thread::spawn(move || {
+            blocked_tx.send(b"b".to_vec());
+            done_tx.send(()).unwrap();
+        });
+
+        // the queue is full, so the second send must still be blocked
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        assert_eq!(rx.recv().unwrap(), b"a".to_vec());
+        done_rx.recv_timeout(Duration::from_secs(1)).expect("blocked send should complete once space frees up");
+        assert_eq!(rx.recv().unwrap(), b"b".to_vec());
+    }
+
+    #[test]
+    fn recv_batch_stops_at_max_without_waiting_for_timeout() {
+        let (tx, rx) = bounded_channel(10, OverflowPolicy::Block);
+        for i in 0..5u8 {
+            tx.send(vec![i]);
+        }
+        let start = Instant::now();
+        let batch = rx.recv_batch(3, Duration::from_secs(5));
+        assert_eq!(batch.len(), 3);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn recv_batch_stops_at_timeout_when_fewer_than_max_are_queued() {
+        let (tx, rx) = bounded_channel(10, OverflowPolicy::Block);
+        tx.send(vec![1]);
+        let batch = rx.recv_batch(5, Duration::from_millis(50));
+        assert_eq!(batch, vec![vec![1]]);
+    }
 }